@@ -0,0 +1,110 @@
+use moka::Expiry;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Mangles characters that are awkward in a cache key/log line into
+/// underscores/dashes. Both cache-key construction and invalidation
+/// patterns must run their input through this so a pattern like a raw
+/// request path still matches the mangled keys stored in the cache.
+pub fn sanitize_cache_key(raw: &str) -> String {
+    raw.replace('*', "_xXx_")
+        .replace(' ', "_")
+        .replace(':', "-")
+        .replace('/', "_")
+}
+
+/// A cached proxy/router response stamped with the TTL derived from the
+/// upstream `Cache-Control` header, so callers can tell a fresh entry from a
+/// stale one that is still being served under stale-while-revalidate.
+#[derive(Clone)]
+pub struct CachedEntry {
+    pub value: Value,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedEntry {
+    pub fn new(value: Value, ttl: Duration) -> Self {
+        Self {
+            value,
+            stored_at: Instant::now(),
+            ttl,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() <= self.ttl
+    }
+}
+
+/// Directives parsed out of an upstream `Cache-Control` header. `s-maxage`
+/// wins over `max-age` since this gateway is a shared cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheControl {
+    pub no_store: bool,
+    max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    pub fn parse(header_value: &str) -> Self {
+        let mut no_store = false;
+        let mut max_age = None;
+        let mut s_maxage = None;
+
+        for directive in header_value.split(',') {
+            let directive = directive.trim();
+            if directive.eq_ignore_ascii_case("no-store") {
+                no_store = true;
+            } else if let Some((name, value)) = directive.split_once('=') {
+                let secs = value.trim().parse::<u64>().ok();
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "max-age" => max_age = secs,
+                    "s-maxage" => s_maxage = secs,
+                    _ => {}
+                }
+            }
+        }
+
+        Self {
+            no_store,
+            max_age: s_maxage.or(max_age).map(Duration::from_secs),
+        }
+    }
+
+    pub fn ttl(&self, default: Duration) -> Duration {
+        self.max_age.unwrap_or(default)
+    }
+}
+
+/// Moka expiry policy that keeps an entry around through its stale grace
+/// window so a stale read can still be served; `CachedEntry::is_fresh`
+/// decides freshness on read, moka just reclaims the entry once the grace
+/// window has elapsed.
+pub struct StaleWhileRevalidateExpiry {
+    pub stale_grace: Duration,
+}
+
+impl Expiry<String, CachedEntry> for StaleWhileRevalidateExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        value: &CachedEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl + self.stale_grace)
+    }
+
+    // Without this, moka falls back to its default of leaving the physical
+    // expiry from the original insert untouched, so a `spawn_revalidation`
+    // refresh with a new TTL would still get reaped on the stale entry's
+    // schedule instead of its own.
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &CachedEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.ttl + self.stale_grace)
+    }
+}
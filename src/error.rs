@@ -0,0 +1,64 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use std::fmt;
+
+/// Unified domain error for the gateway. Every fallible handler returns
+/// `Result<_, AthenaError>` so failures always surface as the same JSON
+/// envelope instead of an ad-hoc body string or a panicking `.unwrap()`.
+#[derive(Debug)]
+pub enum AthenaError {
+    /// The upstream (proxied service or Scylla cluster) returned an error,
+    /// timed out, or sent a response we couldn't make sense of.
+    Upstream(String),
+    /// Failed to obtain or use the shared Scylla session.
+    ScyllaConnect(String),
+    /// The incoming request was malformed (bad query params, bad headers).
+    BadRequest(String),
+    /// A value couldn't be serialized/deserialized as expected.
+    SerdeErr(String),
+}
+
+impl fmt::Display for AthenaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AthenaError::Upstream(msg) => write!(f, "upstream error: {}", msg),
+            AthenaError::ScyllaConnect(msg) => write!(f, "scylla error: {}", msg),
+            AthenaError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            AthenaError::SerdeErr(msg) => write!(f, "serialization error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AthenaError {}
+
+impl ResponseError for AthenaError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AthenaError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AthenaError::ScyllaConnect(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AthenaError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AthenaError::SerdeErr(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "status": "error",
+            "message": self.to_string(),
+            "code": self.status_code().as_u16(),
+        }))
+    }
+}
+
+impl From<serde_json::Error> for AthenaError {
+    fn from(err: serde_json::Error) -> Self {
+        AthenaError::SerdeErr(err.to_string())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for AthenaError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        AthenaError::ScyllaConnect(err.to_string())
+    }
+}
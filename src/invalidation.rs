@@ -0,0 +1,50 @@
+use crate::cache::CachedEntry;
+use moka::future::Cache;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Publish/subscribe bridge for cache invalidation: a change event names the
+/// affected resource (a cache-key pattern or route prefix) and the single
+/// subscriber task drops every matching moka entry.
+pub struct CacheInvalidator {
+    tx: broadcast::Sender<String>,
+}
+
+impl CacheInvalidator {
+    /// Spawns the background subscriber that performs the actual eviction
+    /// and returns a handle to publish invalidation events on.
+    pub fn spawn(cache: Arc<Cache<String, CachedEntry>>) -> Self {
+        let (tx, mut rx) = broadcast::channel(256);
+
+        tokio::spawn(async move {
+            loop {
+                let pattern = match rx.recv().await {
+                    Ok(pattern) => pattern,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Cache invalidation subscriber lagged, skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let predicate_pattern = pattern.clone();
+                match cache.invalidate_entries_if(move |key, _| key.contains(&predicate_pattern)) {
+                    Ok(_) => info!("Invalidated cache entries matching: {}", pattern),
+                    Err(e) => warn!("Failed to invalidate cache pattern {}: {}", pattern, e),
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// Publishes an invalidation event for `pattern`, a substring matched
+    /// against cache keys as stored. Keys are mangled by `sanitize_cache_key`
+    /// and `pattern` is not, so callers pass it through that function first
+    /// (a sanitized route prefix then matches too).
+    pub fn publish(&self, pattern: impl Into<String>) {
+        // No subscribers only happens if the background task panicked; drop
+        // the event rather than fail the caller's request over it.
+        let _ = self.tx.send(pattern.into());
+    }
+}
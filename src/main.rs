@@ -2,30 +2,85 @@ use actix_cors::Cors;
 use actix_web::body::{BoxBody, EitherBody};
 use actix_web::dev::{Service, ServiceResponse};
 use actix_web::http::{header, StatusCode};
-use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
 use dotenv::dotenv;
 use futures::TryStreamExt;
+use metrics_exporter_prometheus::PrometheusHandle;
 use moka::future::Cache;
 use reqwest::{Client, Method};
 use scylla::client::session::Session;
 use scylla::client::session_builder::SessionBuilder;
-use serde_json::{json, Value};
+use scylla::statement::prepared::PreparedStatement;
+use serde_json::json;
 use std::env::var;
 use std::error::Error;
 
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::info;
 use tracing_subscriber::EnvFilter;
 use web::Data;
 
+pub mod cache;
 pub mod drivers;
+pub mod error;
+pub mod invalidation;
 pub mod proxy_request;
+pub mod telemetry;
 
+use crate::cache::{CachedEntry, StaleWhileRevalidateExpiry};
+use crate::error::AthenaError;
+use crate::invalidation::CacheInvalidator;
 use crate::proxy_request::proxy_request;
 pub struct AppState {
-    cache: Arc<Cache<String, Value>>, // Removed Mutex for async-safe cache
+    cache: Arc<Cache<String, CachedEntry>>, // Removed Mutex for async-safe cache
+    cache_invalidator: Arc<CacheInvalidator>,
     client: Client,
+    scylla_session: Arc<Session>,
+    // Prepared statements keyed by the CQL text that produced them, so every
+    // handler shares the same cache instead of re-preparing on every call.
+    scylla_statements: Arc<Cache<String, Arc<PreparedStatement>>>,
+    metrics_handle: PrometheusHandle,
+    // Upstream proxy timeout; shorter-lived Scylla introspection calls use
+    // `scylla_timeout` instead so they fail fast under a stalled cluster.
+    upstream_timeout: Duration,
+    client_body_timeout: Duration,
+    scylla_timeout: Duration,
+    // Upstream JSON bodies at or under this size get buffered and cached;
+    // anything larger (or non-JSON) is streamed straight through uncached.
+    json_cache_max_bytes: u64,
+    // Used when an upstream response carries no `Cache-Control` max-age.
+    cache_default_ttl: Duration,
+}
+
+/// Reads `name` as a whole-number-of-seconds `Duration`, falling back to
+/// `default_secs` if unset or unparsable.
+fn duration_from_env_secs(name: &str, default_secs: u64) -> Duration {
+    Duration::from_secs(
+        var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default_secs),
+    )
+}
+
+impl AppState {
+    /// Returns a prepared statement for `query`, preparing and caching it on
+    /// first use.
+    async fn prepare(
+        &self,
+        query: &str,
+    ) -> Result<Arc<PreparedStatement>, scylla::errors::PrepareError> {
+        if let Some(prepared) = self.scylla_statements.get(query).await {
+            return Ok(prepared);
+        }
+
+        let prepared = Arc::new(self.scylla_session.prepare(query).await?);
+        self.scylla_statements
+            .insert(query.to_string(), prepared.clone())
+            .await;
+        Ok(prepared)
+    }
 }
 
 #[get("/")]
@@ -34,6 +89,35 @@ async fn ping() -> impl Responder {
     HttpResponse::Ok().json(json!({"message": "pong"}))
 }
 
+#[get("/metrics")]
+async fn metrics_endpoint(app_state: Data<AppState>) -> impl Responder {
+    metrics::gauge!("athena_cache_entries").set(app_state.cache.entry_count() as f64);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_state.metrics_handle.render())
+}
+
+#[derive(serde::Deserialize)]
+struct InvalidateCacheRequest {
+    pattern: String,
+}
+
+#[actix_web::post("/cache/invalidate")]
+async fn invalidate_cache_endpoint(
+    payload: web::Json<InvalidateCacheRequest>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AthenaError> {
+    let pattern = payload.into_inner().pattern;
+    if pattern.is_empty() {
+        return Err(AthenaError::BadRequest("pattern must not be empty".to_string()));
+    }
+
+    let sanitized_pattern = crate::cache::sanitize_cache_key(&pattern);
+    info!("Invalidating cache entries matching: {}", pattern);
+    app_state.cache_invalidator.publish(sanitized_pattern);
+    Ok(HttpResponse::Accepted().json(json!({"status": "ok", "pattern": pattern})))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
@@ -43,16 +127,56 @@ async fn main() -> std::io::Result<()> {
         .parse()
         .unwrap_or(4053);
 
-    let cache: Arc<Cache<String, Value>> = Arc::new(
+    let cache_default_ttl: Duration = duration_from_env_secs("XLX_ATHENA_CACHE_DEFAULT_TTL_SECS", 60);
+    let cache_stale_grace: Duration =
+        duration_from_env_secs("XLX_ATHENA_CACHE_STALE_GRACE_SECS", 30);
+    let cache: Arc<Cache<String, CachedEntry>> = Arc::new(
         Cache::builder()
-            .time_to_live(Duration::from_secs(60))
+            .expire_after(StaleWhileRevalidateExpiry {
+                stale_grace: cache_stale_grace,
+            })
+            .support_invalidation_closures()
             .build(),
     );
+    let cache_invalidator: Arc<CacheInvalidator> = Arc::new(CacheInvalidator::spawn(cache.clone()));
     let client: Client = Client::builder()
         .pool_idle_timeout(Duration::from_secs(90))
         .build()
         .unwrap();
-    let app_state: Data<AppState> = Data::new(AppState { cache, client });
+
+    let scylla_uri: String = var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
+    let scylla_session: Arc<Session> = Arc::new(
+        SessionBuilder::new()
+            .known_node(scylla_uri)
+            .build()
+            .await
+            .expect("failed to connect to Scylla"),
+    );
+    let scylla_statements: Arc<Cache<String, Arc<PreparedStatement>>> =
+        Arc::new(Cache::builder().build());
+    let metrics_handle: PrometheusHandle = telemetry::install_recorder();
+    let upstream_timeout: Duration = duration_from_env_secs("XLX_ATHENA_UPSTREAM_TIMEOUT_SECS", 30);
+    let client_body_timeout: Duration =
+        duration_from_env_secs("XLX_ATHENA_CLIENT_BODY_TIMEOUT_SECS", 30);
+    let scylla_timeout: Duration = duration_from_env_secs("XLX_ATHENA_SCYLLA_TIMEOUT_SECS", 10);
+    let json_cache_max_bytes: u64 = var("XLX_ATHENA_JSON_CACHE_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2 * 1024 * 1024);
+
+    let app_state: Data<AppState> = Data::new(AppState {
+        cache,
+        cache_invalidator,
+        client,
+        scylla_session,
+        scylla_statements,
+        metrics_handle,
+        upstream_timeout,
+        client_body_timeout,
+        scylla_timeout,
+        json_cache_max_bytes,
+        cache_default_ttl,
+    });
 
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -62,16 +186,37 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .wrap_fn(|req, srv| {
+                // Use the matched resource pattern, not the literal request
+                // path: `proxy_request` is a catch-all for an arbitrary REST
+                // passthrough, so labeling by path would give every distinct
+                // resource its own unbounded Prometheus time series.
+                let route: String = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "proxy_request".to_string());
+                let started: Instant = Instant::now();
                 let fut = srv.call(req);
                 async move {
                     let mut res: ServiceResponse<EitherBody<BoxBody>> = fut.await?;
                     res.headers_mut()
                         .insert(header::SERVER, "XYLEX/0".parse().unwrap());
+                    metrics::histogram!(
+                        "athena_upstream_request_duration_seconds",
+                        "route" => route.clone(),
+                    )
+                    .record(started.elapsed().as_secs_f64());
+                    metrics::counter!(
+                        "athena_upstream_status_total",
+                        "route" => route,
+                        "status" => res.status().as_u16().to_string(),
+                    )
+                    .increment(1);
                     Ok(res)
                 }
             })
             .app_data(app_state.clone())
             .service(ping)
+            .service(metrics_endpoint)
+            .service(invalidate_cache_endpoint)
             .service(scylla_query_endpoint)
             .service(scylla_query_tables)
             .service(scylla_query_columns)
@@ -91,56 +236,95 @@ fn init_tracing() {
 }
 
 #[get("/scylla")]
-async fn scylla_query_endpoint() -> impl Responder {
-    match scylla_query().await {
-        Ok(_) => HttpResponse::Ok().body("Scylla query executed successfully."),
-        Err(e) => {
-            HttpResponse::InternalServerError().body(format!("Error executing Scylla query: {}", e))
-        }
+async fn scylla_query_endpoint(app_state: Data<AppState>) -> Result<HttpResponse, AthenaError> {
+    match tokio::time::timeout(app_state.scylla_timeout, scylla_query(&app_state)).await {
+        Ok(Ok(_)) => Ok(HttpResponse::Ok().body("Scylla query executed successfully.")),
+        Ok(Err(e)) => Err(AthenaError::from(e)),
+        Err(_) => Err(AthenaError::Upstream("timed out executing Scylla query".to_string())),
     }
 }
 
 #[get("/scylla/tables")]
-async fn scylla_query_tables() -> impl Responder {
-    match scylla_list_tables().await {
-        Ok(tables) => HttpResponse::Ok().json(tables),
-        Err(e) => {
-            HttpResponse::InternalServerError().json(json!({"error": format!("Error listing Scylla tables: {}", e)}))
-        }
+async fn scylla_query_tables(app_state: Data<AppState>) -> Result<HttpResponse, AthenaError> {
+    match tokio::time::timeout(app_state.scylla_timeout, scylla_list_tables(&app_state)).await {
+        Ok(Ok(tables)) => Ok(HttpResponse::Ok().json(tables)),
+        Ok(Err(e)) => Err(AthenaError::from(e)),
+        Err(_) => Err(AthenaError::Upstream("timed out listing Scylla tables".to_string())),
     }
 }
 
-#[get("/scylla/columns")]
-async fn scylla_query_columns(req: HttpRequest) -> impl Responder {
-    if let Some(table_name) = req.query_string().split('=').nth(1) {
-        match scylla_list_columns(table_name).await {
-            Ok(columns) => HttpResponse::Ok().json(columns),
-            Err(e) => HttpResponse::InternalServerError().json(json!({"error": format!("Error listing columns for table {}: {}", table_name, e)})),
-        }
+#[derive(serde::Deserialize)]
+struct ColumnsQuery {
+    table_name: String,
+    #[serde(default = "default_keyspace")]
+    keyspace: String,
+}
+
+#[derive(serde::Deserialize)]
+struct KeyspaceQuery {
+    #[serde(default = "default_keyspace")]
+    keyspace: String,
+}
+
+fn default_keyspace() -> String {
+    "users".to_string()
+}
+
+/// Rejects anything that isn't a plain `[A-Za-z0-9_]+` identifier so a
+/// keyspace name can't be used to smuggle extra CQL into introspection
+/// queries, even though the value is also bound as a prepared parameter.
+///
+/// Handlers call this directly and map a failure to `AthenaError::BadRequest`
+/// before ever reaching the driver call, so an invalid keyspace surfaces as
+/// a 400 rather than collapsing into the generic `ScyllaConnect` (503) path
+/// that driver failures go through.
+fn validate_keyspace(keyspace: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !keyspace.is_empty() && keyspace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
     } else {
-        HttpResponse::BadRequest().body("Missing table_name query parameter")
+        Err(format!("invalid keyspace name: {}", keyspace).into())
+    }
+}
+
+#[get("/scylla/columns")]
+async fn scylla_query_columns(
+    query: web::Query<ColumnsQuery>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AthenaError> {
+    let ColumnsQuery { table_name, keyspace } = query.into_inner();
+    validate_keyspace(&keyspace).map_err(|e| AthenaError::BadRequest(e.to_string()))?;
+    match tokio::time::timeout(
+        app_state.scylla_timeout,
+        scylla_list_columns(&app_state, &keyspace, &table_name),
+    ).await {
+        Ok(Ok(columns)) => Ok(HttpResponse::Ok().json(columns)),
+        Ok(Err(e)) => Err(AthenaError::from(e)),
+        Err(_) => Err(AthenaError::Upstream(format!("timed out listing columns for table {}", table_name))),
     }
 }
 
 
 #[get("/scylla/list_tables")]
-async fn scylla_list_tables_endpoint() -> impl Responder {
-    match get_all_tables_and_columns().await {
-        Ok(tables) => HttpResponse::Ok().json(tables),
-        Err(e) => {
-            HttpResponse::InternalServerError().json(json!({"error": format!("Error listing Scylla tables: {}", e)}))
-        }
+async fn scylla_list_tables_endpoint(
+    query: web::Query<KeyspaceQuery>,
+    app_state: Data<AppState>,
+) -> Result<HttpResponse, AthenaError> {
+    let keyspace = query.into_inner().keyspace;
+    validate_keyspace(&keyspace).map_err(|e| AthenaError::BadRequest(e.to_string()))?;
+    match tokio::time::timeout(
+        app_state.scylla_timeout,
+        get_all_tables_and_columns(&app_state, &keyspace),
+    ).await {
+        Ok(Ok(tables)) => Ok(HttpResponse::Ok().json(tables)),
+        Ok(Err(e)) => Err(AthenaError::from(e)),
+        Err(_) => Err(AthenaError::Upstream("timed out listing Scylla tables".to_string())),
     }
 }
 
 
 
-async fn scylla_query() -> Result<(), Box<dyn Error>> {
-    // Create a new Session which connects to node at 127.0.0.1:9042
-    // (or SCYLLA_URI if specified)
-    let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
-
-    let session: Session = SessionBuilder::new().known_node(uri).build().await?;
+async fn scylla_query(app_state: &AppState) -> Result<(), Box<dyn Error>> {
+    let session: &Arc<Session> = &app_state.scylla_session;
 
     // Create the users keyspace and table with user_id as UUID
     session
@@ -176,12 +360,8 @@ async fn scylla_query() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn scylla_list_tables() -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
-    let session: Session = SessionBuilder::new()
-        .known_node(uri)
-        .build()
-        .await?;
+async fn scylla_list_tables(app_state: &AppState) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let session: &Arc<Session> = &app_state.scylla_session;
 
     let query = "SELECT table_name FROM system_schema.tables WHERE keyspace_name = 'users'";
     let mut iter = session
@@ -199,19 +379,15 @@ async fn scylla_list_tables() -> Result<serde_json::Value, Box<dyn std::error::E
 }
 
 
-async fn scylla_list_columns(table_name: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
-    let session: Session = SessionBuilder::new()
-        .known_node(uri)
-        .build()
-        .await?;
+async fn scylla_list_columns(app_state: &AppState, keyspace: &str, table_name: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    validate_keyspace(keyspace)?;
+    let session: &Arc<Session> = &app_state.scylla_session;
 
-    let query = format!(
-        "SELECT column_name FROM system_schema.columns WHERE keyspace_name = 'users' AND table_name = '{}'",
-        table_name
-    );
+    let prepared = app_state
+        .prepare("SELECT column_name FROM system_schema.columns WHERE keyspace_name = ? AND table_name = ?")
+        .await?;
     let mut iter = session
-        .query_iter(query.as_str(), &[])
+        .execute_iter(prepared.as_ref().clone(), (keyspace, table_name))
         .await?
         .rows_stream::<(String,)>()?;
 
@@ -225,28 +401,27 @@ async fn scylla_list_columns(table_name: &str) -> Result<serde_json::Value, Box<
 }
 
 
-async fn get_all_tables_and_columns() -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
-    let session: Session = SessionBuilder::new()
-        .known_node(uri)
-        .build()
-        .await?;
+async fn get_all_tables_and_columns(app_state: &AppState, keyspace: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    validate_keyspace(keyspace)?;
+    let session: &Arc<Session> = &app_state.scylla_session;
 
-    let tables_query = "SELECT table_name FROM system_schema.tables WHERE keyspace_name = 'users'";
+    let tables_prepared = app_state
+        .prepare("SELECT table_name FROM system_schema.tables WHERE keyspace_name = ?")
+        .await?;
     let mut tables_iter = session
-        .query_iter(tables_query, &[])
+        .execute_iter(tables_prepared.as_ref().clone(), (keyspace,))
         .await?
         .rows_stream::<(String,)>()?;
 
+    let columns_prepared = app_state
+        .prepare("SELECT column_name, type FROM system_schema.columns WHERE keyspace_name = ? AND table_name = ?")
+        .await?;
+
     let mut data = Vec::new();
 
     while let Some((table_name,)) = tables_iter.try_next().await? {
-        let columns_query = format!(
-            "SELECT column_name, type FROM system_schema.columns WHERE keyspace_name = 'users' AND table_name = '{}'",
-            table_name
-        );
         let mut columns_iter = session
-            .query_iter(columns_query.as_str(), &[])
+            .execute_iter(columns_prepared.as_ref().clone(), (keyspace, table_name.as_str()))
             .await?
             .rows_stream::<(String, String)>()?;
 
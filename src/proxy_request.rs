@@ -5,6 +5,7 @@ use actix_web::dev::{ Service, ServiceResponse };
 use actix_web::http::{ header, StatusCode };
 use actix_web::{ get, web, App, HttpRequest, HttpResponse, HttpServer, Responder };
 use dotenv::dotenv;
+use futures::StreamExt;
 use moka::future::Cache;
 use reqwest::{ Client, Method };
 use serde_json::{ json, Value };
@@ -16,7 +17,15 @@ use tracing::info;
 use tracing_subscriber::EnvFilter;
 use web::Data;
 
+use crate::cache::{sanitize_cache_key, CacheControl, CachedEntry};
+use crate::error::AthenaError;
 use crate::AppState;
+
+/// Methods that mutate upstream state; a successful call with one of these
+/// invalidates any cache entries previously stored for the same path.
+fn is_mutating_method(method: &ActixMethod) -> bool {
+    matches!(method, &ActixMethod::POST | &ActixMethod::PUT | &ActixMethod::PATCH | &ActixMethod::DELETE)
+}
 const TARGET_BASE_URL: &str = "https://db-suitsbooks-nl.xylex.cloud";
 
 const HOST_DEXTER: &str = "db-dexter.xylex.cloud";
@@ -24,12 +33,33 @@ const TARGET_BASE_URL_DEXTER: &str = "https://athena.dexter.xylex.cloud";
 
 pub async fn proxy_request(
     req: HttpRequest,
-    body: web::Bytes,
+    mut payload: web::Payload,
     app_state: Data<AppState>
-) -> impl Responder {
+) -> Result<HttpResponse, AthenaError> {
     info!("Starting proxy request processing");
     let client: &Client = &app_state.client;
-    let cache: &Arc<Cache<String, Value>> = &app_state.cache;
+    let cache: &Arc<Cache<String, CachedEntry>> = &app_state.cache;
+
+    let mut body_buf: web::BytesMut = web::BytesMut::new();
+    let body_read: std::result::Result<(), actix_web::Error> = match
+        tokio::time::timeout(app_state.client_body_timeout, async {
+            while let Some(chunk) = payload.next().await {
+                body_buf.extend_from_slice(&chunk?);
+            }
+            Ok::<(), actix_web::Error>(())
+        }).await
+    {
+        Ok(inner) => inner,
+        Err(_) => Err(actix_web::error::ErrorRequestTimeout("client body read timed out")),
+    };
+
+    let body: web::Bytes = match body_read {
+        Ok(()) => body_buf.freeze(),
+        Err(_) => {
+            info!("Timed out waiting for request body from client");
+            return Ok(HttpResponse::RequestTimeout().finish());
+        }
+    };
     let full_url: reqwest::Url = req.full_url();
     let full_url_path: &str = full_url.path();
     let query_params: &str = full_url.query().unwrap_or_default();
@@ -72,16 +102,28 @@ pub async fn proxy_request(
         .get(header::CACHE_CONTROL)
         .cloned();
 
-    let cachekey: String = format!("{}-{}-{}", req.method(), full_url, jwt_token)
-        .replace('*', "_xXx_")
-        .replace(' ', "_")
-        .replace(':', "-")
-        .replace('/', "_");
+    let cachekey: String = sanitize_cache_key(&format!("{}-{}-{}", req.method(), full_url, jwt_token));
 
-    if cache_control_header.as_ref().map_or(true, |h| h != "no-cache") {
-        if let Some(cached_response) = cache.get(&cachekey).await {
-            info!("Cache hit for key: {}", cachekey);
-            return HttpResponse::Ok().json(cached_response);
+    // Populated when a cached entry exists but is past its freshness window;
+    // we serve it immediately and revalidate upstream in the background.
+    let mut stale_value: Option<Value> = None;
+
+    // Mutating requests must always reach upstream: serving them out of the
+    // cache (fresh or stale) would replay an old response as if the write
+    // had happened, or report success before the real call is even sent.
+    if !is_mutating_method(req.method())
+        && cache_control_header.as_ref().map_or(true, |h| h != "no-cache")
+    {
+        if let Some(cached_entry) = cache.get(&cachekey).await {
+            crate::telemetry::record_cache_outcome("proxy", true);
+            if cached_entry.is_fresh() {
+                info!("Cache hit for key: {}", cachekey);
+                return Ok(HttpResponse::Ok().json(cached_entry.value));
+            }
+            info!("Serving stale cache entry for key: {} while revalidating", cachekey);
+            stale_value = Some(cached_entry.value);
+        } else {
+            crate::telemetry::record_cache_outcome("proxy", false);
         }
     }
 
@@ -94,34 +136,64 @@ pub async fn proxy_request(
         _ => Method::GET,
     };
 
-    let mut client_req: reqwest::RequestBuilder = client.request(reqwest_method, &target_url);
+    let mut forwarded_headers: Vec<(reqwest::header::HeaderName, reqwest::header::HeaderValue)> =
+        Vec::new();
+    let mut client_req: reqwest::RequestBuilder = client.request(reqwest_method.clone(), &target_url);
     for (key, value) in req.headers().iter() {
         if key != header::HOST {
             let reqwest_key: reqwest::header::HeaderName = reqwest::header::HeaderName
                 ::from_bytes(key.as_ref())
-                .unwrap();
+                .map_err(|e| AthenaError::BadRequest(format!("invalid header name {}: {}", key, e)))?;
             let reqwest_value: reqwest::header::HeaderValue = reqwest::header::HeaderValue
                 ::from_bytes(value.as_bytes())
-                .unwrap();
+                .map_err(|e| AthenaError::BadRequest(format!("invalid header value for {}: {}", key, e)))?;
+            forwarded_headers.push((reqwest_key.clone(), reqwest_value.clone()));
             client_req = client_req.header(reqwest_key, reqwest_value);
         }
     }
 
     // Set the JWT token as the "apikey" header
     if !jwt_token.is_empty() {
-        client_req = client_req.header("apikey", jwt_token);
+        client_req = client_req.header("apikey", jwt_token.clone());
     }
 
-    match client_req.body(body).send().await {
+    if let Some(stale) = stale_value {
+        spawn_revalidation(
+            app_state.clone(),
+            reqwest_method,
+            target_url.clone(),
+            forwarded_headers,
+            jwt_token,
+            body,
+            cachekey,
+        );
+        return Ok(HttpResponse::Ok().json(stale));
+    }
+
+    let send_result = match
+        tokio::time::timeout(app_state.upstream_timeout, client_req.body(body).send()).await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            info!("Upstream request to {} timed out", target_url);
+            return Ok(HttpResponse::GatewayTimeout().finish());
+        }
+    };
+
+    match send_result {
         Ok(res) => {
             info!("Received response from target URL: {}", target_url);
-            let status_code: StatusCode = StatusCode::from_u16(res.status().as_u16()).unwrap();
+            let status_code: StatusCode = StatusCode::from_u16(res.status().as_u16())
+                .map_err(|e| AthenaError::Upstream(format!("invalid upstream status code: {}", e)))?;
             let headers: reqwest::header::HeaderMap = res.headers().clone();
-            let body_bytes: web::Bytes = res.bytes().await.unwrap_or_default();
-            let json_body: Value = serde_json::from_slice(&body_bytes).unwrap_or(Value::Null);
-            cache.insert(cachekey, json_body.clone()).await;
-            let mut response: actix_web::HttpResponseBuilder = HttpResponse::build(status_code);
+            let content_type: String = headers
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let is_json: bool = content_type.contains("json");
 
+            let mut response: actix_web::HttpResponseBuilder = HttpResponse::build(status_code);
             for (key, value) in headers.iter() {
                 if
                     ![
@@ -133,25 +205,149 @@ pub async fn proxy_request(
                 {
                     let actix_key: header::HeaderName = actix_web::http::header::HeaderName
                         ::from_bytes(key.as_str().as_bytes())
-                        .unwrap();
+                        .map_err(|e| AthenaError::Upstream(format!("invalid upstream header name {}: {}", key, e)))?;
                     let actix_value: header::HeaderValue = actix_web::http::header::HeaderValue
                         ::from_bytes(value.as_bytes())
-                        .unwrap();
-                    if actix_key == header::CONTENT_TYPE {
-                        response.append_header((
-                            actix_key,
-                            header::HeaderValue::from_static("application/json"),
-                        ));
-                    } else {
-                        response.append_header((actix_key, actix_value));
+                        .map_err(|e| AthenaError::Upstream(format!("invalid upstream header value for {}: {}", key, e)))?;
+                    response.append_header((actix_key, actix_value));
+                }
+            }
+
+            let cache_control: CacheControl = headers
+                .get(reqwest::header::CACHE_CONTROL)
+                .and_then(|value| value.to_str().ok())
+                .map(CacheControl::parse)
+                .unwrap_or_default();
+
+            if is_mutating_method(req.method()) && status_code.is_success() {
+                info!("Invalidating cache entries for path {} after mutating request", path);
+                app_state.cache_invalidator.publish(sanitize_cache_key(&path));
+            }
+
+            if is_json {
+                // Buffer only up to the cache cap so a large (or mislabeled)
+                // JSON body can't be pulled fully into memory; once the cap
+                // is crossed, stream the already-read prefix followed by the
+                // rest of the upstream body instead of caching it.
+                let mut buffered: web::BytesMut = web::BytesMut::new();
+                let mut body_stream = res.bytes_stream();
+                let mut exceeded_cap = false;
+                while let Some(chunk) = body_stream.next().await {
+                    let chunk = chunk.map_err(|e| AthenaError::Upstream(format!("error reading upstream body: {}", e)))?;
+                    buffered.extend_from_slice(&chunk);
+                    if (buffered.len() as u64) > app_state.json_cache_max_bytes {
+                        exceeded_cap = true;
+                        break;
+                    }
+                }
+
+                if exceeded_cap {
+                    info!("Upstream JSON body for {} exceeded cache cap, streaming uncached", target_url);
+                    let prefix: web::Bytes = buffered.freeze();
+                    let combined = futures::stream::once(async move { Ok::<_, actix_web::Error>(prefix) })
+                        .chain(body_stream.map(|chunk| chunk.map_err(|e| actix_web::error::ErrorBadGateway(e))));
+                    Ok(response.streaming(combined))
+                } else {
+                    let body_bytes: web::Bytes = buffered.freeze();
+                    if !cache_control.no_store {
+                        if let Ok(json_body) = serde_json::from_slice::<Value>(&body_bytes) {
+                            let ttl = cache_control.ttl(app_state.cache_default_ttl);
+                            cache.insert(cachekey, CachedEntry::new(json_body, ttl)).await;
+                        }
                     }
+                    Ok(response.body(body_bytes))
                 }
+            } else {
+                info!("Streaming upstream response for {} uncached (content-type={})", target_url, content_type);
+                let stream = res
+                    .bytes_stream()
+                    .map(|chunk| chunk.map_err(|e| actix_web::error::ErrorBadGateway(e)));
+                Ok(response.streaming(stream))
             }
-            response.body(body_bytes)
         }
         Err(e) => {
             info!("Error sending request to target URL: {}", e);
-            HttpResponse::InternalServerError().finish()
+            Err(AthenaError::Upstream(e.to_string()))
         },
     }
 }
+
+/// Refreshes a stale cache entry in the background so the caller that
+/// triggered the refresh doesn't pay the upstream latency; the next request
+/// for this key picks up the fresh value once it lands.
+fn spawn_revalidation(
+    app_state: Data<AppState>,
+    method: Method,
+    target_url: String,
+    headers: Vec<(reqwest::header::HeaderName, reqwest::header::HeaderValue)>,
+    jwt_token: String,
+    body: web::Bytes,
+    cachekey: String,
+) {
+    tokio::spawn(async move {
+        let mut client_req: reqwest::RequestBuilder =
+            app_state.client.request(method, &target_url);
+        for (key, value) in headers {
+            client_req = client_req.header(key, value);
+        }
+        if !jwt_token.is_empty() {
+            client_req = client_req.header("apikey", jwt_token);
+        }
+
+        let res = match
+            tokio::time::timeout(app_state.upstream_timeout, client_req.body(body).send()).await
+        {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                info!("Background revalidation of {} failed: {}", target_url, e);
+                return;
+            }
+            Err(_) => {
+                info!("Background revalidation of {} timed out", target_url);
+                return;
+            }
+        };
+
+        let content_type: String = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let cache_control: CacheControl = res
+            .headers()
+            .get(reqwest::header::CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .map(CacheControl::parse)
+            .unwrap_or_default();
+
+        if !content_type.contains("json") || cache_control.no_store {
+            return;
+        }
+
+        // Nobody is waiting on this response, so there's no stream to hand a
+        // cap-exceeding body off to like the foreground path does; just stop
+        // reading and drop the refresh instead of buffering it all in.
+        let mut buffered: web::BytesMut = web::BytesMut::new();
+        let mut body_stream = res.bytes_stream();
+        while let Some(chunk) = body_stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    info!("Background revalidation of {} failed reading body: {}", target_url, e);
+                    return;
+                }
+            };
+            buffered.extend_from_slice(&chunk);
+            if (buffered.len() as u64) > app_state.json_cache_max_bytes {
+                info!("Background revalidation of {} exceeded cache cap, dropping refresh", target_url);
+                return;
+            }
+        }
+
+        if let Ok(json_body) = serde_json::from_slice::<Value>(&buffered.freeze()) {
+            let ttl = cache_control.ttl(app_state.cache_default_ttl);
+            app_state.cache.insert(cachekey, CachedEntry::new(json_body, ttl)).await;
+        }
+    });
+}
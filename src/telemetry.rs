@@ -0,0 +1,21 @@
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+/// Installs the process-wide Prometheus recorder and returns a handle that
+/// renders the current metrics snapshot for the `/metrics` endpoint.
+pub fn install_recorder() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .install_recorder()
+        .expect("failed to install Prometheus recorder")
+}
+
+/// Records a cache lookup outcome for `route`, shared by every handler that
+/// consults the moka response cache.
+pub fn record_cache_outcome(route: &str, hit: bool) {
+    let outcome = if hit { "hit" } else { "miss" };
+    metrics::counter!(
+        "athena_cache_requests_total",
+        "route" => route.to_string(),
+        "outcome" => outcome,
+    )
+    .increment(1);
+}